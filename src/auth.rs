@@ -0,0 +1,87 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+type HmacSha256 = Hmac<Sha256>;
+
+static TICKET_TTL_SECS: u64 = 2 * 60 * 60;
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+/// Issues and verifies Proxmox-style signed tickets of the form
+/// `user:expiry:base64(HMAC-SHA256(signing_key, "user:expiry"))`, guarding
+/// every `/secrets` and `/devices` request.
+pub struct TicketAuth {
+    username: String,
+    password: String,
+    signing_key: Vec<u8>,
+}
+
+impl TicketAuth {
+    pub fn new(username: String, password: String, signing_key: String) -> Self {
+        TicketAuth { username, password, signing_key: signing_key.into_bytes() }
+    }
+
+    pub fn login(&self, username: &str, password: &str) -> Option<String> {
+        let user_ok: bool = username.as_bytes().ct_eq(self.username.as_bytes()).into();
+        let pass_ok: bool = password.as_bytes().ct_eq(self.password.as_bytes()).into();
+        if user_ok && pass_ok {
+            Some(self.sign(username, now() + TICKET_TTL_SECS))
+        } else {
+            None
+        }
+    }
+
+    pub fn verify(&self, ticket: &str) -> bool {
+        let mut parts = ticket.splitn(3, ':');
+        let (user, expiry, sig) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(user), Some(expiry), Some(sig)) => (user, expiry, sig),
+            _ => return false,
+        };
+        let expiry: u64 = match expiry.parse() {
+            Ok(expiry) => expiry,
+            Err(_) => return false,
+        };
+        if expiry <= now() {
+            return false;
+        }
+        let expected = self.sign(user, expiry);
+        expected.as_bytes().ct_eq(ticket.as_bytes()).into()
+    }
+
+    fn sign(&self, username: &str, expiry: u64) -> String {
+        let payload = format!("{}:{}", username, expiry);
+        let mut mac = HmacSha256::new_varkey(&self.signing_key).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        format!("{}:{}", payload, base64::encode(mac.finalize().into_bytes()))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+pub fn extract_ticket<'a>(headers: &'a hyper::HeaderMap) -> Option<&'a str> {
+    if let Some(value) = headers.get(hyper::header::AUTHORIZATION) {
+        if let Ok(value) = value.to_str() {
+            return Some(value.trim_start_matches("Bearer ").trim());
+        }
+    }
+    if let Some(value) = headers.get(hyper::header::COOKIE) {
+        if let Ok(value) = value.to_str() {
+            for cookie in value.split(';') {
+                let cookie = cookie.trim();
+                if cookie.starts_with("ticket=") {
+                    return Some(&cookie["ticket=".len()..]);
+                }
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::{Async, Future, Stream};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Request};
+use sha1::{Digest, Sha1};
+use tokio::io::{split, write_all, AsyncRead};
+
+/// Size of the scratch buffer used to drain (and discard) inbound bytes on
+/// an `/events` connection, which carries no client-to-server payload.
+static DISCARD_BUF_SIZE: usize = 1024;
+
+static WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Set,
+    Delete,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ChangeEvent {
+    pub op: ChangeOp,
+    pub domain: String,
+}
+
+pub type EventSenders = Arc<RwLock<HashMap<String, UnboundedSender<ChangeEvent>>>>;
+
+pub fn is_upgrade_request(req: &Request<Body>) -> bool {
+    req.headers().get(hyper::header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.eq_ignore_ascii_case("websocket"))
+}
+
+pub fn accept_key(client_key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(client_key.as_bytes());
+    hasher.update(WS_GUID.as_bytes());
+    base64::encode(hasher.finalize())
+}
+
+pub fn broadcast(senders: &EventSenders, originator: &str, event: ChangeEvent) {
+    let senders = senders.read().unwrap();
+    for (device_id, sender) in senders.iter() {
+        if device_id != originator {
+            let _ = sender.unbounded_send(event.clone());
+        }
+    }
+}
+
+pub fn serve(upgraded: Upgraded, device_id: String, senders: EventSenders) -> impl Future<Item=(), Error=()> {
+    let (sender, receiver) = unbounded::<ChangeEvent>();
+    senders.write().unwrap().insert(device_id.clone(), sender);
+
+    let (reader, writer) = split(upgraded);
+    tokio::spawn(discard_inbound(reader).map_err(|_| ()));
+
+    let cleanup_senders = senders.clone();
+    let cleanup_device_id = device_id.clone();
+    receiver
+        .map_err(|_| ())
+        .fold(writer, |writer, event| {
+            let payload = serde_json::to_string(&event).unwrap();
+            write_all(writer, encode_text_frame(&payload))
+                .map(|(writer, _)| writer)
+                .map_err(|_| ())
+        })
+        .map(|_| ())
+        .then(move |result| {
+            cleanup_senders.write().unwrap().remove(&cleanup_device_id);
+            result
+        })
+}
+
+/// Drains `reader` into a small fixed-size buffer, discarding every chunk
+/// read, until EOF or an error. This device doesn't send a payload over
+/// `/events`, so unlike `tokio::io::read_to_end` this never grows an
+/// unbounded buffer for a connection that stays open indefinitely.
+fn discard_inbound<R: AsyncRead>(mut reader: R) -> impl Future<Item=(), Error=io::Error> {
+    let mut buf = [0u8; DISCARD_BUF_SIZE];
+    futures::future::poll_fn(move || loop {
+        match reader.poll_read(&mut buf) {
+            Ok(Async::Ready(0)) => return Ok(Async::Ready(())),
+            Ok(Async::Ready(_)) => continue,
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(err) => return Err(err),
+        }
+    })
+}
+
+fn encode_text_frame(payload: &str) -> Vec<u8> {
+    let bytes = payload.as_bytes();
+    let mut frame = Vec::with_capacity(bytes.len() + 10);
+    frame.push(0x81); // FIN + text opcode
+    let len = bytes.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= 0xFFFF {
+        frame.push(126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(bytes);
+    frame
+}
@@ -3,8 +3,8 @@ extern crate bson;
 #[macro_use]
 extern crate serde_derive;
 
-use std::collections::HashSet;
-use std::env::args;
+use std::collections::{HashMap, HashSet};
+use std::env::{args, var};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, SocketAddr};
@@ -18,13 +18,21 @@ use hyper::body::Body;
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, Service};
 
+use crate::auth::{extract_ticket, LoginRequest, TicketAuth};
 use crate::error::{ErrorInfo, Never};
+use crate::events::{ChangeEvent, ChangeOp, EventSenders};
 use crate::http::{empty_response, json_builder, json_ok};
-use crate::storage::{MongoStorage, Secret, Storage};
+use crate::metrics::Metrics;
+use crate::storage::{EncryptedStorage, MongoStorage, Secret, Storage};
 
+mod auth;
+mod events;
 mod http;
 mod error;
+mod metrics;
 mod storage;
+mod tls;
+mod totp;
 
 static DEFAULT_BIND: &str = "127.0.0.1:9000";
 static CONFIG_FILE: &str = "config.json";
@@ -37,8 +45,20 @@ pub struct DbConfig {
     username: Option<String>,
     password: Option<String>,
     pool_size: u32,
+    /// Falls back to the `BEARTAMER_MASTER_KEY` env var when absent.
+    encryption_passphrase: Option<String>,
+    encryption_salt: Option<String>,
+    auth_username: String,
+    auth_password: String,
+    auth_signing_key: String,
+    /// When both TLS paths are absent the server falls back to plaintext TCP;
+    /// setting only one of them is a startup error.
+    tls_cert_path: Option<String>,
+    tls_key_path: Option<String>,
 }
 
+static DEFAULT_MASTER_KEY_ENV: &str = "BEARTAMER_MASTER_KEY";
+
 fn main() {
     let address = {
         let addr = args().nth(1).unwrap_or_else(|| {
@@ -70,17 +90,82 @@ fn main() {
         .build(conn_mgr)
         .expect("Pool connection error");
 
-    let storage = MongoStorage::new(pool);
+    let passphrase = db_conf.encryption_passphrase.clone()
+        .or_else(|| var(DEFAULT_MASTER_KEY_ENV).ok())
+        .expect("no encryption passphrase configured (set db config `encryption_passphrase` or BEARTAMER_MASTER_KEY)");
+    let salt = db_conf.encryption_salt.clone()
+        .expect("db config `encryption_salt` must be set for envelope encryption");
+
+    let storage = EncryptedStorage::new(MongoStorage::new(pool), &passphrase, salt.as_bytes());
     let devices = Arc::new(RwLock::new(HashSet::new()));
+    let auth = Arc::new(TicketAuth::new(db_conf.auth_username, db_conf.auth_password, db_conf.auth_signing_key));
+    let events: EventSenders = Arc::new(RwLock::new(HashMap::new()));
+    let metrics = Arc::new(Metrics::new());
+
+    match (db_conf.tls_cert_path, db_conf.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let tls_config = tls::load_server_config(&tls::TlsConfig { cert_path, key_path })
+                .expect("failed to load TLS certificate/key");
+            let incoming = tls::TlsIncoming::bind(&address, tls_config)
+                .expect("failed to bind TLS listener");
+            let server = Server::builder(incoming)
+                .serve(make_service_fn(move |conn: &tls::TlsAddrStream| future::ok::<_, Never>(SecretService::new(conn.remote_addr(), storage.clone(), devices.clone(), auth.clone(), events.clone(), metrics.clone()))))
+                .map_err(|e| panic!("Error: {:?}", e));
+            rt::run(rt::lazy(move || {
+                rt::spawn(server);
+                Ok(())
+            }));
+        }
+        (Some(_), None) | (None, Some(_)) =>
+            panic!("db config `tls_cert_path` and `tls_key_path` must be set together"),
+        (None, None) => {
+            eprintln!("No TLS certificate configured, serving plaintext");
+            let server = Server::bind(&address)
+                .serve(make_service_fn(move |addr: &AddrStream| future::ok::<_, Never>(SecretService::new(addr.remote_addr(), storage.clone(), devices.clone(), auth.clone(), events.clone(), metrics.clone()))))
+                .map_err(|e| panic!("Error: {:?}", e));
+            rt::run(rt::lazy(move || {
+                rt::spawn(server);
+                Ok(())
+            }));
+        }
+    }
+}
 
-    let server = Server::bind(&address)
-        .serve(make_service_fn(move |addr: &AddrStream| future::ok::<_, Never>(SecretService::new(addr.remote_addr(), storage.clone(), devices.clone()))))
-        .map_err(|e| panic!("Error: {:?}", e));
+#[derive(Serialize)]
+pub struct TotpCode {
+    code: String,
+    seconds_remaining: u64,
+}
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    #[serde(default)]
+    get: Vec<String>,
+    #[serde(default)]
+    set: Vec<Secret>,
+    #[serde(default)]
+    delete: Vec<String>,
+}
 
-    rt::run(rt::lazy(move || {
-        rt::spawn(server);
-        Ok(())
-    }));
+#[derive(Serialize)]
+pub struct BatchSetStatus {
+    domain: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    get: HashMap<String, Option<Secret>>,
+    /// Per-domain storage errors from the `get` batch, kept out of `get`
+    /// itself so that map stays `{<domain>: <Secret|null>}` as documented.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    get_errors: HashMap<String, String>,
+    set: Vec<BatchSetStatus>,
+    delete: HashMap<String, bool>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    delete_errors: HashMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -116,11 +201,14 @@ pub struct SecretService<S> {
     client_addr: SocketAddr,
     storage: S,
     devices: Arc<RwLock<HashSet<DeviceInfo>>>,
+    auth: Arc<TicketAuth>,
+    events: EventSenders,
+    metrics: Arc<Metrics>,
 }
 
 impl<S> SecretService<S> {
-    fn new(client_addr: SocketAddr, storage: S, devices: Arc<RwLock<HashSet<DeviceInfo>>>) -> Self {
-        SecretService { client_addr, storage, devices }
+    fn new(client_addr: SocketAddr, storage: S, devices: Arc<RwLock<HashSet<DeviceInfo>>>, auth: Arc<TicketAuth>, events: EventSenders, metrics: Arc<Metrics>) -> Self {
+        SecretService { client_addr, storage, devices, auth, events, metrics }
     }
 }
 
@@ -135,16 +223,84 @@ impl<S: Storage + Clone + 'static> Service for SecretService<S> {
         let uri = req.uri();
         let mut path = uri.path().trim_start_matches("/").split("/");
 
-        match path.next() {
+        let route = path.next();
+
+        self.metrics.record_request(method.as_str(), route.unwrap_or(""));
+
+        if route == Some("metrics") {
+            self.metrics.set_devices(self.devices.read().unwrap().len());
+            return Either::A(future::ok(Response::builder()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .status(StatusCode::OK)
+                .body(Body::from(self.metrics.encode())).unwrap()));
+        }
+
+        if route == Some("login") {
+            let auth = self.auth.clone();
+            let resp = req.into_body()
+                .map_err(|err| {
+                    panic!("Error processing request: {}", err);
+                })
+                .concat2()
+                .and_then(move |c| {
+                    match String::from_utf8(c.to_vec()) {
+                        Err(err) =>
+                            ErrorInfo::new(&format!("invalid data: {}", err))
+                                .resp(StatusCode::BAD_REQUEST),
+                        Ok(s) => match serde_json::from_str::<LoginRequest>(&s) {
+                            Err(err) =>
+                                ErrorInfo::new(&format!("invalid json: {}", err))
+                                    .resp(StatusCode::BAD_REQUEST),
+                            Ok(login) => match auth.login(&login.username, &login.password) {
+                                Some(ticket) => json_ok(&ticket),
+                                None => ErrorInfo::new("invalid credentials").resp(StatusCode::UNAUTHORIZED),
+                            }
+                        }
+                    }
+                });
+            return Either::B(Box::new(resp));
+        }
+
+        match extract_ticket(req.headers()) {
+            Some(ticket) if self.auth.verify(ticket) => (),
+            _ => return Either::A(ErrorInfo::new("authentication required").resp(StatusCode::UNAUTHORIZED)),
+        }
+
+        match route {
             Some("secrets") => (),
             Some("devices") => {
                 let devices = self.devices.read().unwrap();
                 return Either::A(json_ok(&*devices));
             },
+            Some("events") => {
+                if !events::is_upgrade_request(&req) {
+                    return Either::A(ErrorInfo::new("expected a WebSocket upgrade").resp(StatusCode::BAD_REQUEST));
+                }
+                let key = match req.headers().get("Sec-WebSocket-Key").and_then(|v| v.to_str().ok()) {
+                    Some(key) => key.to_string(),
+                    None => return Either::A(ErrorInfo::new("missing Sec-WebSocket-Key").resp(StatusCode::BAD_REQUEST)),
+                };
+                let device_id = serde_urlencoded::from_str::<DeviceInfo>(uri.query().unwrap_or(""))
+                    .map(|info| info.device_id)
+                    .unwrap_or_else(|_| String::from("unknown"));
+                let accept = events::accept_key(&key);
+                let events = self.events.clone();
+                let mut req = req;
+                rt::spawn(hyper::upgrade::on(&mut req)
+                    .map_err(|err| panic!("upgrade error: {}", err))
+                    .and_then(move |upgraded| events::serve(upgraded, device_id, events)));
+                return Either::A(future::ok(Response::builder()
+                    .status(StatusCode::SWITCHING_PROTOCOLS)
+                    .header("Upgrade", "websocket")
+                    .header("Connection", "Upgrade")
+                    .header("Sec-WebSocket-Accept", accept)
+                    .body(Body::empty()).unwrap()));
+            }
             _ => return Either::A(ErrorInfo::new("API not found").resp(StatusCode::NOT_FOUND)),
         }
 
         let domain = path.next().map_or_else(|| String::from(""), |d| d.to_string());
+        let sub_route = path.next();
 
         let mut devices = self.devices.write().unwrap();
         let mut device_info = {
@@ -156,24 +312,102 @@ impl<S: Storage + Clone + 'static> Service for SecretService<S> {
         if !device_info.ip_addrs.contains(&client_ip) {
             device_info.ip_addrs.push(client_ip);
         }
+        let originator_id = device_info.device_id.clone();
         devices.insert(device_info);
 
         match method {
             &Method::GET if domain.is_empty() =>
-                Either::A(match self.storage.get_all() {
+                Either::A(match self.metrics.time_storage("get_all", || self.storage.get_all()) {
                     Ok(values) => json_ok(&values),
                     Err(err) => ErrorInfo::new(&format!("storage error: {}", err))
                         .resp(StatusCode::INTERNAL_SERVER_ERROR),
                 }),
+            &Method::GET if sub_route == Some("code") =>
+                Either::A(match self.metrics.time_storage("get", || self.storage.get(&domain)) {
+                    Ok(Some(ref secret)) if !secret.is_totp() =>
+                        ErrorInfo::new("not a TOTP secret").resp(StatusCode::BAD_REQUEST),
+                    Ok(Some(ref secret)) => match secret.totp_code() {
+                        Ok((code, seconds_remaining)) => json_ok(&TotpCode { code, seconds_remaining }),
+                        Err(err) => ErrorInfo::new(&err).resp(StatusCode::BAD_REQUEST),
+                    },
+                    Ok(None) => ErrorInfo::new("Domain not found").resp(StatusCode::NOT_FOUND),
+                    Err(err) => ErrorInfo::new(&format!("storage error: {}", err))
+                        .resp(StatusCode::INTERNAL_SERVER_ERROR),
+                }),
             &Method::GET =>
-                Either::A(match self.storage.get(&domain) {
+                Either::A(match self.metrics.time_storage("get", || self.storage.get(&domain)) {
                     Ok(Some(secret)) => json_ok(&secret),
                     Ok(None) => ErrorInfo::new("Domain not found").resp(StatusCode::NOT_FOUND),
                     Err(err) => ErrorInfo::new(&format!("storage error: {}", err))
                         .resp(StatusCode::INTERNAL_SERVER_ERROR),
                 }),
+            &Method::POST if domain == "batch" => {
+                let storage = self.storage.clone();
+                let events = self.events.clone();
+                let metrics = self.metrics.clone();
+                let resp = req.into_body()
+                    .map_err(|err| {
+                        panic!("Error processing request: {}", err);
+                    })
+                    .concat2()
+                    .and_then(move |c| {
+                        match String::from_utf8(c.to_vec()) {
+                            Err(err) =>
+                                ErrorInfo::new(&format!("invalid data: {}", err))
+                                    .resp(StatusCode::BAD_REQUEST),
+                            Ok(s) => match serde_json::from_str::<BatchRequest>(&s) {
+                                Err(err) =>
+                                    ErrorInfo::new(&format!("invalid json: {}", err))
+                                        .resp(StatusCode::BAD_REQUEST),
+                                Ok(batch) => {
+                                    let mut get = HashMap::new();
+                                    let mut get_errors = HashMap::new();
+                                    for domain in batch.get {
+                                        match metrics.time_storage("get", || storage.get(&domain)) {
+                                            Ok(secret) => { get.insert(domain, secret); }
+                                            Err(err) => { get_errors.insert(domain, err.to_string()); }
+                                        }
+                                    }
+
+                                    let set = batch.set.into_iter().map(|secret| match secret.validate() {
+                                        Err(err) => BatchSetStatus { domain: secret.domain, ok: false, error: Some(err) },
+                                        Ok(()) => {
+                                            let domain = secret.domain.clone();
+                                            match metrics.time_storage("set", || storage.set(secret)) {
+                                                Ok(()) => {
+                                                    events::broadcast(&events, &originator_id, ChangeEvent { op: ChangeOp::Set, domain: domain.clone() });
+                                                    BatchSetStatus { domain, ok: true, error: None }
+                                                }
+                                                Err(err) => BatchSetStatus { domain, ok: false, error: Some(err.to_string()) },
+                                            }
+                                        }
+                                    }).collect::<Vec<_>>();
+
+                                    let mut delete = HashMap::new();
+                                    let mut delete_errors = HashMap::new();
+                                    for domain in batch.delete {
+                                        match metrics.time_storage("delete", || storage.delete(&domain)) {
+                                            Ok(deleted) => {
+                                                if deleted {
+                                                    events::broadcast(&events, &originator_id, ChangeEvent { op: ChangeOp::Delete, domain: domain.clone() });
+                                                }
+                                                delete.insert(domain, deleted);
+                                            }
+                                            Err(err) => { delete_errors.insert(domain, err.to_string()); }
+                                        }
+                                    }
+
+                                    json_ok(&BatchResponse { get, get_errors, set, delete, delete_errors })
+                                }
+                            }
+                        }
+                    });
+                Either::B(Box::new(resp))
+            }
             &Method::PUT | &Method::POST => {
                 let storage = self.storage.clone();
+                let events = self.events.clone();
+                let metrics = self.metrics.clone();
                 let resp = req.into_body()
                     .map_err(|err| {
                         panic!("Error processing request: {}", err);
@@ -188,11 +422,18 @@ impl<S: Storage + Clone + 'static> Service for SecretService<S> {
                                 Err(err) =>
                                     ErrorInfo::new(&format!("invalid json: {}", err))
                                         .resp(StatusCode::BAD_REQUEST),
-                                Ok(secret) => {
-                                    match storage.set(secret) {
-                                        Ok(()) => empty_response(),
-                                        Err(err) => ErrorInfo::new(&format!("storage error: {}", err))
-                                            .resp(StatusCode::INTERNAL_SERVER_ERROR),
+                                Ok(secret) => match secret.validate() {
+                                    Err(err) => ErrorInfo::new(&err).resp(StatusCode::BAD_REQUEST),
+                                    Ok(()) => {
+                                        let domain = secret.domain.clone();
+                                        match metrics.time_storage("set", || storage.set(secret)) {
+                                            Ok(()) => {
+                                                events::broadcast(&events, &originator_id, ChangeEvent { op: ChangeOp::Set, domain });
+                                                empty_response()
+                                            }
+                                            Err(err) => ErrorInfo::new(&format!("storage error: {}", err))
+                                                .resp(StatusCode::INTERNAL_SERVER_ERROR),
+                                        }
                                     }
                                 }
                             }
@@ -201,8 +442,11 @@ impl<S: Storage + Clone + 'static> Service for SecretService<S> {
                 Either::B(Box::new(resp))
             }
             &Method::DELETE =>
-                Either::A(match self.storage.delete(&domain) {
-                    Ok(true) => empty_response(),
+                Either::A(match self.metrics.time_storage("delete", || self.storage.delete(&domain)) {
+                    Ok(true) => {
+                        events::broadcast(&self.events, &originator_id, ChangeEvent { op: ChangeOp::Delete, domain: domain.clone() });
+                        empty_response()
+                    }
                     Ok(false) => ErrorInfo::new("domain not found").resp(StatusCode::NOT_FOUND),
                     Err(err) => ErrorInfo::new(&format!("Storage error: {:?}", err))
                         .resp(StatusCode::INTERNAL_SERVER_ERROR),
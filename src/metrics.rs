@@ -0,0 +1,60 @@
+use prometheus::{Encoder, HistogramVec, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+pub struct Metrics {
+    registry: Registry,
+    requests_total: IntCounterVec,
+    storage_duration_seconds: HistogramVec,
+    storage_errors_total: IntCounterVec,
+    devices: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests_total = IntCounterVec::new(
+            Opts::new("beartamer_requests_total", "Total HTTP requests by method and path"),
+            &["method", "path"],
+        ).expect("valid metric");
+        let storage_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("beartamer_storage_duration_seconds", "Storage operation latency"),
+            &["op"],
+        ).expect("valid metric");
+        let storage_errors_total = IntCounterVec::new(
+            Opts::new("beartamer_storage_errors_total", "Total storage operation errors"),
+            &["op"],
+        ).expect("valid metric");
+        let devices = IntGauge::new("beartamer_devices", "Number of known devices").expect("valid metric");
+
+        registry.register(Box::new(requests_total.clone())).expect("register metric");
+        registry.register(Box::new(storage_duration_seconds.clone())).expect("register metric");
+        registry.register(Box::new(storage_errors_total.clone())).expect("register metric");
+        registry.register(Box::new(devices.clone())).expect("register metric");
+
+        Metrics { registry, requests_total, storage_duration_seconds, storage_errors_total, devices }
+    }
+
+    pub fn record_request(&self, method: &str, path: &str) {
+        self.requests_total.with_label_values(&[method, path]).inc();
+    }
+
+    pub fn time_storage<T, E>(&self, op: &str, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        let timer = self.storage_duration_seconds.with_label_values(&[op]).start_timer();
+        let result = f();
+        timer.observe_duration();
+        if result.is_err() {
+            self.storage_errors_total.with_label_values(&[op]).inc();
+        }
+        result
+    }
+
+    pub fn set_devices(&self, count: usize) {
+        self.devices.set(count as i64);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).expect("encode metrics");
+        buffer
+    }
+}
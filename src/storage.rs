@@ -1,12 +1,18 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::sync::{Arc, RwLock};
 
+use argon2::{Argon2, Algorithm, Params, Version};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead};
 use mongodb::{Bson, from_bson, to_bson};
 use mongodb::coll::Collection;
 use mongodb::db::ThreadedDatabase;
 use r2d2::Pool;
 use r2d2_mongodb::MongodbConnectionManager;
+use rand::rngs::OsRng;
+use rand::RngCore;
 
 use crate::error::Never;
 use mongodb::coll::options::UpdateOptions;
@@ -14,15 +20,69 @@ use mongodb::coll::options::UpdateOptions;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Secret {
     r#type: SecretType,
-    domain: String,
+    pub(crate) domain: String,
     username: String,
     password: String,
+    #[serde(default)]
+    digits: Option<u32>,
+    #[serde(default)]
+    period: Option<u64>,
+}
+
+static DEFAULT_TOTP_DIGITS: u32 = 6;
+static DEFAULT_TOTP_PERIOD: u64 = 30;
+static MIN_TOTP_DIGITS: u32 = 1;
+static MAX_TOTP_DIGITS: u32 = 9;
+static MIN_TOTP_PERIOD: u64 = 1;
+static MAX_TOTP_PERIOD: u64 = 300;
+
+impl Secret {
+    /// Validates type-specific invariants before the secret is persisted;
+    /// for `Totp` that means a decodable base32 seed and in-range
+    /// `digits`/`period`, since both feed unchecked arithmetic in
+    /// `totp::totp_now`.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        match self.r#type {
+            SecretType::Totp => {
+                crate::totp::decode_seed(&self.password)
+                    .map_err(|_| String::from("malformed base32 TOTP seed"))?;
+                let digits = self.digits.unwrap_or(DEFAULT_TOTP_DIGITS);
+                if digits < MIN_TOTP_DIGITS || digits > MAX_TOTP_DIGITS {
+                    return Err(format!("digits must be between {} and {}", MIN_TOTP_DIGITS, MAX_TOTP_DIGITS));
+                }
+                let period = self.period.unwrap_or(DEFAULT_TOTP_PERIOD);
+                if period < MIN_TOTP_PERIOD || period > MAX_TOTP_PERIOD {
+                    return Err(format!("period must be between {} and {}", MIN_TOTP_PERIOD, MAX_TOTP_PERIOD));
+                }
+                Ok(())
+            }
+            SecretType::Password => Ok(()),
+        }
+    }
+
+    /// Computes the current TOTP code and seconds until rollover. Only
+    /// meaningful for `SecretType::Totp` secrets.
+    pub(crate) fn totp_code(&self) -> Result<(String, u64), String> {
+        let seed = crate::totp::decode_seed(&self.password)
+            .map_err(|_| String::from("malformed base32 TOTP seed"))?;
+        let digits = self.digits.unwrap_or(DEFAULT_TOTP_DIGITS);
+        let period = self.period.unwrap_or(DEFAULT_TOTP_PERIOD);
+        Ok(crate::totp::totp_now(&seed, period, digits))
+    }
+
+    pub(crate) fn is_totp(&self) -> bool {
+        match self.r#type {
+            SecretType::Totp => true,
+            SecretType::Password => false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum SecretType {
-    Password
+    Password,
+    Totp,
 }
 
 #[derive(Clone)]
@@ -113,3 +173,126 @@ impl Storage for MongoStorage {
         coll.delete_one(doc! { "domain": domain }, None).map(|res| res.deleted_count > 0)
     }
 }
+
+/// A sealed (nonce, ciphertext) pair, JSON-encoded into the `password`
+/// string field rather than stored as a top-level BSON sub-document:
+/// `Secret` and `Storage` are shared with `MemStorage`, which has no notion
+/// of encryption at all, so the sealed form has to round-trip through the
+/// same `String` field both backends already agree on. The tradeoff is that
+/// Mongo can't query or index `nonce`/`ciphertext` separately. `ciphertext`
+/// includes the AEAD authentication tag.
+#[derive(Serialize, Deserialize)]
+struct SealedField {
+    nonce: String,
+    ciphertext: String,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let params = Params::new(64 * 1024, 3, 1, Some(32)).expect("invalid Argon2 params");
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+    let mut key = [0u8; 32];
+    argon2.hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .expect("key derivation failed");
+    key
+}
+
+#[derive(Debug)]
+pub enum EncryptedStorageError<E> {
+    Inner(E),
+    Malformed,
+    AuthenticationFailed,
+}
+
+impl<E: fmt::Display> fmt::Display for EncryptedStorageError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptedStorageError::Inner(err) => write!(f, "{}", err),
+            EncryptedStorageError::Malformed => write!(f, "malformed encrypted field"),
+            EncryptedStorageError::AuthenticationFailed => write!(f, "decryption authentication failed"),
+        }
+    }
+}
+
+impl<E: Error> Error for EncryptedStorageError<E> {}
+
+/// Envelope-encrypts `password` before delegating to an inner [`Storage`]
+/// backend, so at-rest data never holds plaintext credentials.
+#[derive(Clone)]
+pub struct EncryptedStorage<S> {
+    inner: S,
+    cipher: Arc<ChaCha20Poly1305>,
+}
+
+impl<S> EncryptedStorage<S> {
+    pub fn new(inner: S, passphrase: &str, salt: &[u8]) -> Self {
+        let key = derive_key(passphrase, salt);
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        EncryptedStorage { inner, cipher: Arc::new(cipher) }
+    }
+
+    fn seal(&self, plaintext: &str) -> String {
+        let mut nonce_bytes = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce_bytes);
+        let ciphertext = self.cipher.encrypt(Nonce::from_slice(&nonce_bytes), plaintext.as_bytes())
+            .expect("encryption failure");
+        serde_json::to_string(&SealedField {
+            nonce: base64::encode(&nonce_bytes),
+            ciphertext: base64::encode(&ciphertext),
+        }).expect("serialize sealed field")
+    }
+
+    fn open<E>(&self, sealed: &str) -> Result<String, EncryptedStorageError<E>> {
+        let sealed: SealedField = serde_json::from_str(sealed)
+            .map_err(|_| EncryptedStorageError::Malformed)?;
+        let nonce_bytes = base64::decode(&sealed.nonce).map_err(|_| EncryptedStorageError::Malformed)?;
+        let ciphertext = base64::decode(&sealed.ciphertext).map_err(|_| EncryptedStorageError::Malformed)?;
+        let plaintext = self.cipher.decrypt(Nonce::from_slice(&nonce_bytes), ciphertext.as_ref())
+            .map_err(|_| EncryptedStorageError::AuthenticationFailed)?;
+        String::from_utf8(plaintext).map_err(|_| EncryptedStorageError::Malformed)
+    }
+
+    fn seal_secret(&self, mut secret: Secret) -> Secret {
+        secret.password = self.seal(&secret.password);
+        secret
+    }
+
+    fn open_secret<E>(&self, mut secret: Secret) -> Result<Secret, EncryptedStorageError<E>> {
+        secret.password = self.open(&secret.password)?;
+        Ok(secret)
+    }
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S> {
+    type Error = EncryptedStorageError<S::Error>;
+
+    fn get_all(&self) -> Result<Vec<Secret>, Self::Error> {
+        Ok(self.inner.get_all().map_err(EncryptedStorageError::Inner)?
+            .into_iter()
+            .filter_map(|secret| {
+                let domain = secret.domain.clone();
+                match self.open_secret::<S::Error>(secret) {
+                    Ok(secret) => Some(secret),
+                    Err(err) => {
+                        eprintln!("storage: dropping undecryptable secret for domain {}: {}", domain, err);
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    fn get(&self, domain: &str) -> Result<Option<Secret>, Self::Error> {
+        match self.inner.get(domain).map_err(EncryptedStorageError::Inner)? {
+            Some(secret) => self.open_secret(secret).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, secret: Secret) -> Result<(), Self::Error> {
+        self.inner.set(self.seal_secret(secret)).map_err(EncryptedStorageError::Inner)
+    }
+
+    fn delete(&self, domain: &str) -> Result<bool, Self::Error> {
+        self.inner.delete(domain).map_err(EncryptedStorageError::Inner)
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::stream::FuturesUnordered;
+use futures::{Async, Future, Poll, Stream};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Delay;
+use tokio_rustls::{TlsAcceptor, TlsStream};
+
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+pub fn load_server_config(tls: &TlsConfig) -> io::Result<ServerConfig> {
+    let cert_chain = {
+        let file = File::open(&tls.cert_path)?;
+        certs(&mut BufReader::new(file))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid TLS certificate chain"))?
+    };
+
+    let key = {
+        let file = File::open(&tls.key_path)?;
+        let mut keys = pkcs8_private_keys(&mut BufReader::new(file))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid TLS private key"))?;
+        if keys.is_empty() {
+            let file = File::open(&tls.key_path)?;
+            keys = rsa_private_keys(&mut BufReader::new(file))
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid TLS private key"))?;
+        }
+        keys.into_iter().next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found in TLS key file"))?
+    };
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(cert_chain, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("invalid certificate/key pair: {}", err)))?;
+    Ok(config)
+}
+
+pub struct TlsAddrStream {
+    stream: TlsStream<TcpStream, rustls::ServerSession>,
+    remote_addr: SocketAddr,
+}
+
+impl TlsAddrStream {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+impl io::Read for TlsAddrStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl io::Write for TlsAddrStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for TlsAddrStream {}
+
+impl AsyncWrite for TlsAddrStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
+
+/// Consecutive accept errors tolerated per `poll` before giving up on this
+/// round instead of busy-spinning the executor thread on fd exhaustion
+/// (`EMFILE`/`ENFILE`).
+static MAX_CONSECUTIVE_ACCEPT_ERRORS: u32 = 16;
+
+pub struct TlsIncoming {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<Box<dyn Future<Item=TlsAddrStream, Error=io::Error> + Send>>,
+    backoff: Option<Delay>,
+}
+
+impl TlsIncoming {
+    pub fn bind(addr: &SocketAddr, config: ServerConfig) -> io::Result<Self> {
+        Ok(TlsIncoming {
+            listener: TcpListener::bind(addr)?,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            handshakes: FuturesUnordered::new(),
+            backoff: None,
+        })
+    }
+}
+
+impl Stream for TlsIncoming {
+    type Item = TlsAddrStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(backoff) = &mut self.backoff {
+            match backoff.poll() {
+                Ok(Async::Ready(())) => self.backoff = None,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => panic!("TLS accept backoff timer failed: {}", err),
+            }
+        }
+
+        let mut consecutive_errors = 0;
+        loop {
+            match self.listener.poll_accept() {
+                Ok(Async::Ready((tcp, remote_addr))) => {
+                    consecutive_errors = 0;
+                    let accept = self.acceptor.accept(tcp)
+                        .map(move |stream| TlsAddrStream { stream, remote_addr });
+                    self.handshakes.push(Box::new(accept));
+                }
+                Ok(Async::NotReady) => break,
+                Err(err) => {
+                    eprintln!("TLS: failed to accept TCP connection: {}", err);
+                    consecutive_errors += 1;
+                    if consecutive_errors >= MAX_CONSECUTIVE_ACCEPT_ERRORS {
+                        // Likely fd exhaustion (EMFILE/ENFILE), where poll_accept
+                        // would otherwise keep failing synchronously forever.
+                        // Arm a timer so we're polled again after a delay
+                        // instead of busy-spinning this executor thread.
+                        self.backoff = Some(Delay::new(Instant::now() + Duration::from_millis(100)));
+                        // Polling the fresh delay registers this task for wake-up.
+                        return self.poll();
+                    }
+                }
+            }
+        }
+        match self.handshakes.poll() {
+            Ok(Async::Ready(Some(stream))) => Ok(Async::Ready(Some(stream))),
+            Ok(Async::Ready(None)) | Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => {
+                eprintln!("TLS: handshake failed: {}", err);
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
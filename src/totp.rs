@@ -0,0 +1,41 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+
+type HmacSha1 = Hmac<Sha1>;
+
+#[derive(Debug)]
+pub struct InvalidSeed;
+
+/// Decodes an RFC 4648 base32 TOTP seed (case-insensitive, padding optional).
+pub fn decode_seed(seed: &str) -> Result<Vec<u8>, InvalidSeed> {
+    base32::decode(base32::Alphabet::RFC4648 { padding: false }, &seed.to_uppercase())
+        .ok_or(InvalidSeed)
+}
+
+/// RFC 4226 HOTP: HMAC-SHA1 over the big-endian counter, then dynamic
+/// truncation to `digits` decimal digits.
+fn hotp(seed: &[u8], counter: u64, digits: u32) -> String {
+    let mut mac = HmacSha1::new_varkey(seed).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = ((u32::from(hash[offset] & 0x7f) << 24)
+        | (u32::from(hash[offset + 1]) << 16)
+        | (u32::from(hash[offset + 2]) << 8)
+        | u32::from(hash[offset + 3])) as u64;
+
+    let code = truncated % 10u64.pow(digits);
+    format!("{:0width$}", code, width = digits as usize)
+}
+
+/// RFC 6238 TOTP: the current code for `seed`/`period`/`digits`, plus the
+/// number of seconds remaining until it rolls over.
+pub fn totp_now(seed: &[u8], period: u64, digits: u32) -> (String, u64) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let counter = now / period;
+    let seconds_remaining = period - (now % period);
+    (hotp(seed, counter, digits), seconds_remaining)
+}